@@ -0,0 +1,35 @@
+use once_cell::sync::Lazy;
+use quick_cache::sync::Cache;
+use sha2::{Digest, Sha256};
+
+/// Rendering a size table costs a round trip to the external render
+/// worker; cache a handful of distinct tables per warm execution
+/// environment so items that share a size chart only render it once.
+const SIZE_TABLE_RENDER_CACHE_CAPACITY: usize = 64;
+
+static SIZE_TABLE_RENDER_CACHE: Lazy<Cache<String, Vec<u8>>> =
+    Lazy::new(|| Cache::new(SIZE_TABLE_RENDER_CACHE_CAPACITY));
+
+/// Hex-encoded SHA-256 over `parts`, used both as the size-table render
+/// cache key and as the "already built" content hash stored on the zip.
+pub fn content_hash<'a>(parts: impl IntoIterator<Item = &'a str>) -> String {
+    let mut hasher = Sha256::new();
+    for (i, part) in parts.into_iter().enumerate() {
+        if i > 0 {
+            hasher.update(b"|");
+        }
+        hasher.update(part.as_bytes());
+    }
+    hex::encode(hasher.finalize())
+}
+
+/// Returns a previously rendered size-table image for `hash`, if this
+/// execution environment already rendered the same table.
+pub fn get_rendered_size_table(hash: &str) -> Option<Vec<u8>> {
+    SIZE_TABLE_RENDER_CACHE.get(hash)
+}
+
+/// Caches a freshly rendered size-table image under `hash`.
+pub fn put_rendered_size_table(hash: String, bytes: Vec<u8>) {
+    SIZE_TABLE_RENDER_CACHE.insert(hash, bytes);
+}
@@ -0,0 +1,103 @@
+use lambda_runtime::Error;
+use serde::Deserialize;
+use std::time::Duration;
+
+/// Environment variable prefix used by [`Config::from_env`], e.g.
+/// `PHDB_SIZE_TABLE_RENDER_AUTH_TOKEN`.
+const ENV_PREFIX: &str = "PHDB_";
+
+/// Runtime configuration for the function, loaded once at startup from
+/// the environment. Centralizing these here keeps the render worker's
+/// auth token out of source and lets the function be redeployed against
+/// different buckets/regions without recompiling.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Config {
+    #[serde(default = "default_size_table_render_url")]
+    pub size_table_render_url: String,
+    pub size_table_render_auth_token: String,
+    #[serde(default = "default_region")]
+    pub region: String,
+    #[serde(default = "default_item_images_bucket")]
+    pub item_images_bucket: String,
+    #[serde(default = "default_bundled_images_bucket")]
+    pub bundled_images_bucket: String,
+    #[serde(default = "default_function_resource_bucket")]
+    pub function_resource_bucket: String,
+    #[serde(default = "default_font_key")]
+    pub font_key: String,
+    #[serde(default = "default_http_timeout_secs")]
+    pub http_timeout_secs: u64,
+    #[serde(default = "default_image_fetch_concurrency")]
+    pub image_fetch_concurrency: usize,
+}
+
+fn default_size_table_render_url() -> String {
+    "https://size-table-render.eliamo.workers.dev/image".to_string()
+}
+
+fn default_region() -> String {
+    "ap-northeast-1".to_string()
+}
+
+fn default_item_images_bucket() -> String {
+    "phitemspics".to_string()
+}
+
+fn default_bundled_images_bucket() -> String {
+    "phbundledimages".to_string()
+}
+
+fn default_function_resource_bucket() -> String {
+    "phfunctionresource".to_string()
+}
+
+fn default_font_key() -> String {
+    "TaipeiSansTCBeta-Light.ttf".to_string()
+}
+
+fn default_http_timeout_secs() -> u64 {
+    10
+}
+
+fn default_image_fetch_concurrency() -> usize {
+    8
+}
+
+impl Config {
+    /// Loads and validates config from `PHDB_`-prefixed environment
+    /// variables. Unlike the `.unwrap()` this replaces, an unset timeout
+    /// falls back to 10 seconds, and an unparseable or zero timeout is
+    /// reported as a startup error instead of a panic.
+    pub fn from_env() -> Result<Self, Error> {
+        let config: Config = envy::prefixed(ENV_PREFIX).from_env()?;
+        if config.http_timeout_secs == 0 {
+            return Err(Error::from(format!(
+                "{}HTTP_TIMEOUT_SECS must be greater than zero",
+                ENV_PREFIX
+            )));
+        }
+        if config.image_fetch_concurrency == 0 {
+            return Err(Error::from(format!(
+                "{}IMAGE_FETCH_CONCURRENCY must be greater than zero",
+                ENV_PREFIX
+            )));
+        }
+        if config.region.parse::<rusoto_core::Region>().is_err() {
+            return Err(Error::from(format!(
+                "{}REGION is not a valid AWS region: {}",
+                ENV_PREFIX, config.region
+            )));
+        }
+        Ok(config)
+    }
+
+    pub fn http_timeout(&self) -> Duration {
+        Duration::from_secs(self.http_timeout_secs)
+    }
+
+    pub fn region(&self) -> rusoto_core::Region {
+        self.region
+            .parse()
+            .expect("region was validated in Config::from_env")
+    }
+}
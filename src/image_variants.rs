@@ -0,0 +1,75 @@
+use image::{imageops::FilterType, DynamicImage, ImageFormat};
+use lambda_runtime::Error;
+use serde::Deserialize;
+
+/// Output format for a generated image variant, selected per request.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum VariantFormat {
+    Webp,
+    Jpeg,
+}
+
+impl Default for VariantFormat {
+    fn default() -> Self {
+        VariantFormat::Webp
+    }
+}
+
+/// A single resized/encoded copy of a source image, tagged with the target
+/// width it was generated for so callers can build a stable entry name.
+pub struct ImageVariant {
+    pub width: u32,
+    pub bytes: Vec<u8>,
+}
+
+/// Decodes `source_bytes` and produces one [`ImageVariant`] per distinct
+/// resized width in `widths`, downscaling with Lanczos3 while preserving
+/// aspect ratio. A requested width that is not smaller than the source is
+/// clamped to the source width so variants never upscale; once a width
+/// clamps this way, any later requested width that clamps to the same
+/// value is skipped so two entries never end up with identical bytes
+/// under different, misleading filenames.
+pub fn make_variants(
+    source_bytes: &[u8],
+    widths: &[u32],
+    format: VariantFormat,
+) -> Result<Vec<ImageVariant>, Error> {
+    let image = image::load_from_memory(source_bytes)?;
+    let source_width = image.width();
+    let mut variants = Vec::with_capacity(widths.len());
+    let mut produced_widths = std::collections::HashSet::with_capacity(widths.len());
+    for &width in widths {
+        let target_width = width.min(source_width);
+        if !produced_widths.insert(target_width) {
+            continue;
+        }
+        let resized = if target_width == source_width {
+            image.clone()
+        } else {
+            let target_height =
+                (image.height() as u64 * target_width as u64 / source_width as u64) as u32;
+            image.resize(target_width, target_height, FilterType::Lanczos3)
+        };
+        let bytes = encode(&resized, format)?;
+        variants.push(ImageVariant {
+            width: target_width,
+            bytes,
+        });
+    }
+    Ok(variants)
+}
+
+fn encode(image: &DynamicImage, format: VariantFormat) -> Result<Vec<u8>, Error> {
+    match format {
+        VariantFormat::Webp => {
+            let encoder = webp::Encoder::from_image(image).map_err(Error::from)?;
+            Ok(encoder.encode(80.0).to_vec())
+        }
+        VariantFormat::Jpeg => {
+            let mut bytes: Vec<u8> = Vec::new();
+            image.write_to(&mut std::io::Cursor::new(&mut bytes), ImageFormat::Jpeg)?;
+            Ok(bytes)
+        }
+    }
+}
@@ -1,18 +1,23 @@
 use bytes::Buf;
+use config::Config;
 use image_combiner::Processor;
+use image_variants::{make_variants, VariantFormat};
 use lambda_runtime::{service_fn, Error, LambdaEvent};
-use rusoto_core::{Region, RusotoError};
-use rusoto_s3::{GetObjectError, GetObjectRequest, S3Client, S3};
+use rusoto_core::RusotoError;
+use rusoto_s3::{GetObjectError, GetObjectRequest, HeadObjectRequest, S3Client, S3};
+use s3_zip_upload::{CompressionMode, DeflateLevel};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use std::{
-    io::{Cursor, Read, Write},
-    time::Duration,
-};
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::io::AsyncReadExt;
-use zip::write::SimpleFileOptions;
 
-#[derive(Clone, Debug, Deserialize)]
+mod cache;
+mod config;
+mod image_variants;
+mod s3_zip_upload;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 struct ItemSize {
     size_table: Option<SizeTable>,
     #[allow(dead_code)]
@@ -20,7 +25,7 @@ struct ItemSize {
     size_zh: String,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 struct SizeTable {
     #[allow(dead_code)]
     pub head: Vec<String>,
@@ -58,31 +63,28 @@ struct Response {
     message: String,
 }
 
-const SIZE_TABLE_RENDER_URL: &str = "https://size-table-render.eliamo.workers.dev/image";
-const SIZE_TABLE_RENDER_AUTH_TOKEN: &str = "kBvz7@EwBA2PpPXu8hP*xCygfDGr2vgo8yo44CMn";
-
 struct SizeTableRenderClient {
     client: reqwest::Client,
+    url: String,
     auth_token: String,
+    timeout: Duration,
 }
 
 impl SizeTableRenderClient {
-    pub fn new() -> Self {
-        let client = reqwest::Client::new();
-        let auth_token = SIZE_TABLE_RENDER_AUTH_TOKEN.to_string();
-        Self { client, auth_token }
+    pub fn new(config: &Config) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url: config.size_table_render_url.clone(),
+            auth_token: config.size_table_render_auth_token.clone(),
+            timeout: config.http_timeout(),
+        }
     }
 
     pub async fn render_size_table(&self, size_table: &SizeTable) -> Result<Vec<u8>, Error> {
         let response = self
             .client
-            .post(SIZE_TABLE_RENDER_URL)
-            .timeout(Duration::from_secs(
-                std::env::var("HTTP_TIMEOUT")
-                    .unwrap()
-                    .parse::<u64>()
-                    .unwrap(),
-            ))
+            .post(&self.url)
+            .timeout(self.timeout)
             .bearer_auth(&self.auth_token)
             .json(&SizeTableRenderRequestBody::from(size_table))
             .send()
@@ -96,13 +98,17 @@ const SEPARATOR_PATTERN: &[char] = &['，', '、', ','];
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
-    let func = service_fn(func);
-    lambda_runtime::run(func).await?;
+    let config = Arc::new(Config::from_env()?);
+    let handler = service_fn(move |event| {
+        let config = config.clone();
+        async move { func(event, config).await }
+    });
+    lambda_runtime::run(handler).await?;
     Ok(())
 }
 
-async fn func(event: LambdaEvent<Value>) -> Result<Value, Error> {
-    let render_client = SizeTableRenderClient::new();
+async fn func(event: LambdaEvent<Value>, config: Arc<Config>) -> Result<Value, Error> {
+    let render_client = SizeTableRenderClient::new(&config);
     let item_code = match event.payload.get("item_code") {
         Some(string) => string.as_str().unwrap().to_owned(),
         None => {
@@ -145,92 +151,207 @@ async fn func(event: LambdaEvent<Value>) -> Result<Value, Error> {
             }
         }
     };
-    let s3_client = S3Client::new(Region::ApNortheast1);
-    let mut image_bytes: Vec<Vec<u8>> = Vec::new();
+    let variant_widths: Vec<u32> = match event.payload.get("variant_widths") {
+        Some(value) => match serde_json::from_value(value.to_owned()) {
+            Ok(widths) => widths,
+            Err(err) => {
+                return Ok(json!(Response {
+                    result: "error".to_string(),
+                    message: format!("error when parse variant_widths error: {:?}", err)
+                }));
+            }
+        },
+        None => Vec::new(),
+    };
+    let variant_format = match event.payload.get("variant_format") {
+        Some(value) => match serde_json::from_value::<VariantFormat>(value.to_owned()) {
+            Ok(format) => format,
+            Err(err) => {
+                return Ok(json!(Response {
+                    result: "error".to_string(),
+                    message: format!("error when parse variant_format error: {:?}", err)
+                }));
+            }
+        },
+        None => VariantFormat::default(),
+    };
+    let compression_mode = match event.payload.get("compression_mode") {
+        Some(value) => match serde_json::from_value::<CompressionMode>(value.to_owned()) {
+            Ok(mode) => mode,
+            Err(err) => {
+                return Ok(json!(Response {
+                    result: "error".to_string(),
+                    message: format!("error when parse compression_mode error: {:?}", err)
+                }));
+            }
+        },
+        None => CompressionMode::default(),
+    };
+    let deflate_level = match event.payload.get("compression_level") {
+        Some(value) => match serde_json::from_value::<DeflateLevel>(value.to_owned()) {
+            Ok(level) => level,
+            Err(err) => {
+                return Ok(json!(Response {
+                    result: "error".to_string(),
+                    message: format!("error when parse compression_level error: {:?}", err)
+                }));
+            }
+        },
+        None => DeflateLevel::default(),
+    };
+    let s3_client = S3Client::new(config.region());
+    let semaphore = std::sync::Arc::new(tokio::sync::Semaphore::new(config.image_fetch_concurrency));
+
+    // Gather ETags with a cheap HeadObject pass first, so a cache hit
+    // below can skip the costly full-body GetObject downloads entirely.
+    let mut head_checks = Vec::with_capacity(image_count as usize);
     for no in 1..=image_count {
-        let request = GetObjectRequest {
-            bucket: "phitemspics".to_string(),
-            key: format!("{}_{}.jpeg", item_code, no),
-            ..Default::default()
-        };
-        let res = match s3_client.get_object(request).await {
-            Ok(object) => object,
+        let semaphore = semaphore.clone();
+        let s3_client = s3_client.clone();
+        let item_code = item_code.clone();
+        let item_images_bucket = config.item_images_bucket.clone();
+        head_checks.push(tokio::spawn(async move {
+            let _permit = semaphore
+                .acquire_owned()
+                .await
+                .expect("image fetch semaphore should not be closed");
+            head_image(&s3_client, &item_images_bucket, &item_code, no).await
+        }));
+    }
+    let mut image_etags: Vec<String> = Vec::with_capacity(image_count as usize);
+    let mut head_checks = head_checks.into_iter();
+    while let Some(head_check) = head_checks.next() {
+        match head_check.await {
+            Ok(etag) => image_etags.extend(etag),
             Err(err) => {
-                if let RusotoError::Service(GetObjectError::NoSuchKey(_)) = err {
-                    println!("no such key:{}", format_args!("{}_{}.jpeg", item_code, no));
-                    continue;
+                // The runtime is reused across warm invocations, so a
+                // dropped JoinHandle alone wouldn't stop the remaining
+                // HeadObject calls from running into the next one.
+                for remaining in head_checks.by_ref() {
+                    remaining.abort();
                 }
                 println!("error happened:{}", err);
                 return Ok(json!(Response {
                     result: "error".to_string(),
-                    message: "error when get item image".to_string()
+                    message: "error when check item image".to_string()
                 }));
             }
-        };
-        let res_body = res.body.unwrap();
-        let mut image_byte: Vec<u8> = Vec::new();
-        if let Err(err) = res_body
-            .into_async_read()
-            .read_to_end(&mut image_byte)
-            .await
-        {
-            println!("error happened:{}", err);
-            return Ok(json!(Response {
-                result: "error".to_string(),
-                message: "error when read image bytes".to_string()
-            }));
         }
-        println!(
-            "get image:{},len:{}",
-            format_args!("{}_{}.jpeg", item_code, no),
-            image_byte.len()
-        );
-        image_bytes.push(image_byte);
     }
-    /////////////////////////////////////////////
-    // if request not have body then this item not have a size data
-    let processor = Processor::default();
-    if item_size_opt.is_none() {
-        let mut zip_buf: Cursor<Vec<u8>> = Cursor::new(Vec::new());
-        let mut zip_writer = zip::ZipWriter::new(&mut zip_buf);
-        let zip_options =
-            SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
-        for (i, image_byte) in image_bytes.into_iter().enumerate() {
-            if let Err(err) =
-                zip_writer.start_file(format!("{}_{}.jpg", item_code, i + 1), zip_options)
-            {
+
+    let item_size_json = match &item_size_opt {
+        Some(item_size) => serde_json::to_string(item_size).unwrap_or_default(),
+        None => "none".to_string(),
+    };
+    let variant_widths_json = serde_json::to_string(&variant_widths).unwrap_or_default();
+    let mut hash_parts: Vec<String> = Vec::with_capacity(image_etags.len() + 6);
+    hash_parts.push(item_code.clone());
+    hash_parts.extend(image_etags);
+    hash_parts.push(item_size_json);
+    hash_parts.push(variant_widths_json);
+    hash_parts.push(format!("{:?}", variant_format));
+    hash_parts.push(format!("{:?}", compression_mode));
+    hash_parts.push(format!("{:?}", deflate_level));
+    let content_hash = cache::content_hash(hash_parts.iter().map(String::as_str));
+    let bundle_key = format!("{}.zip", item_code);
+    if s3_zip_upload::existing_zip_matches(
+        &s3_client,
+        &config.bundled_images_bucket,
+        &bundle_key,
+        &content_hash,
+    )
+    .await
+    {
+        println!("zip for {} already built, content hash {}", item_code, content_hash);
+        return Ok(json!(Response {
+            result: "ok".to_string(),
+            message: "".to_string()
+        }));
+    }
+
+    // No cached zip matches this hash; only now pay for the full-body
+    // image downloads.
+    let mut fetches = Vec::with_capacity(image_count as usize);
+    for no in 1..=image_count {
+        let semaphore = semaphore.clone();
+        let s3_client = s3_client.clone();
+        let item_code = item_code.clone();
+        let item_images_bucket = config.item_images_bucket.clone();
+        fetches.push(tokio::spawn(async move {
+            let _permit = semaphore
+                .acquire_owned()
+                .await
+                .expect("image fetch semaphore should not be closed");
+            fetch_image(&s3_client, &item_images_bucket, &item_code, no).await
+        }));
+    }
+    let mut fetched_images: Vec<Option<(String, Vec<u8>)>> = Vec::with_capacity(image_count as usize);
+    let mut fetches = fetches.into_iter();
+    while let Some(fetch) = fetches.next() {
+        match fetch.await {
+            Ok(Ok(image)) => fetched_images.push(image),
+            Ok(Err(err)) => {
+                // Same reasoning as the HeadObject pass above: abort the
+                // rest instead of letting dropped handles keep running
+                // into the next warm invocation.
+                for remaining in fetches.by_ref() {
+                    remaining.abort();
+                }
+                println!("error happened:{}", err);
                 return Ok(json!(Response {
                     result: "error".to_string(),
-                    message: format!("error when zip start file error:{}", err)
+                    message: "error when get item image".to_string()
                 }));
-            };
-
-            if let Err(err) = zip_writer.write_all(&image_byte) {
+            }
+            Err(err) => {
+                for remaining in fetches.by_ref() {
+                    remaining.abort();
+                }
+                println!("error happened:{}", err);
                 return Ok(json!(Response {
                     result: "error".to_string(),
-                    message: format!("error when zip write file error:{}", err)
+                    message: "error when get item image".to_string()
                 }));
-            };
-        }
-        if let Err(err) = zip_writer.finish() {
-            return Ok(json!(Response {
-                result: "error".to_string(),
-                message: format!("error when zip finish error:{}", err)
-            }));
+            }
         }
+    }
+    let image_bytes: Vec<Vec<u8>> = fetched_images
+        .into_iter()
+        .flatten()
+        .map(|(_, bytes)| bytes)
+        .collect();
 
-        let zip_file_buf = zip_buf.into_inner();
-        println!("read buf length:{}", zip_file_buf.len());
-        let put_request = rusoto_s3::PutObjectRequest {
-            bucket: "phbundledimages".to_string(),
-            body: Some(zip_file_buf.into()),
-            key: format!("{}.zip", item_code),
-            ..Default::default()
-        };
-        if s3_client.put_object(put_request).await.is_err() {
+    /////////////////////////////////////////////
+    // if request not have body then this item not have a size data
+    let processor = Processor::default();
+    if item_size_opt.is_none() {
+        let mut entries = Vec::new();
+        for (i, image_byte) in image_bytes.into_iter().enumerate() {
+            match build_image_entries(&item_code, i + 1, &image_byte, &variant_widths, variant_format) {
+                Ok(image_entries) => entries.extend(image_entries),
+                Err(message) => {
+                    return Ok(json!(Response {
+                        result: "error".to_string(),
+                        message
+                    }));
+                }
+            }
+        }
+        if let Err(err) = s3_zip_upload::stream_zip_to_s3(
+            &s3_client,
+            &config.bundled_images_bucket,
+            &bundle_key,
+            entries,
+            compression_mode,
+            deflate_level,
+            &content_hash,
+        )
+        .await
+        {
+            println!("error happened:{:?}", err);
             return Ok(json!(Response {
                 result: "error".to_string(),
-                message: "error when put image".to_string()
+                message: format!("error when build and upload zip error: {:?}", err)
             }));
         }
         return Ok(json!(Response {
@@ -239,7 +360,13 @@ async fn func(event: LambdaEvent<Value>) -> Result<Value, Error> {
         }));
     };
     ////////////////////////////////////////////////
-    let font_bytes = match get_font_file("TaipeiSansTCBeta-Light.ttf", &s3_client).await {
+    let font_bytes = match get_font_file(
+        &config.function_resource_bucket,
+        &config.font_key,
+        &s3_client,
+    )
+    .await
+    {
         Ok(font_byte) => font_byte,
         Err(err) => {
             println!("get font file error happened:{:?}", err);
@@ -259,14 +386,24 @@ async fn func(event: LambdaEvent<Value>) -> Result<Value, Error> {
                 .map(|s| s.to_string())
                 .collect();
             size_table.head = table_head;
-            match render_client.render_size_table(&size_table).await {
-                Ok(bytes) => bytes,
-                Err(err) => {
-                    println!("error happened:{:?}", err);
-                    return Ok(json!(Response {
-                        result: "error".to_string(),
-                        message: format!("error when create table image error: {:?}", err)
-                    }));
+            let table_hash = cache::content_hash([serde_json::to_string(&size_table)
+                .unwrap_or_default()
+                .as_str()]);
+            match cache::get_rendered_size_table(&table_hash) {
+                Some(bytes) => bytes,
+                None => {
+                    let bytes = match render_client.render_size_table(&size_table).await {
+                        Ok(bytes) => bytes,
+                        Err(err) => {
+                            println!("error happened:{:?}", err);
+                            return Ok(json!(Response {
+                                result: "error".to_string(),
+                                message: format!("error when create table image error: {:?}", err)
+                            }));
+                        }
+                    };
+                    cache::put_rendered_size_table(table_hash, bytes.clone());
+                    bytes
                 }
             }
         }
@@ -286,59 +423,35 @@ async fn func(event: LambdaEvent<Value>) -> Result<Value, Error> {
         }
     };
 
-    let mut zip_buf: Cursor<Vec<u8>> = Cursor::new(Vec::new());
-    let mut zip_writer = zip::ZipWriter::new(&mut zip_buf);
-    let zip_options =
-        SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+    let mut entries = Vec::new();
     for (i, image_byte) in image_bytes.into_iter().enumerate() {
-        if let Err(err) = zip_writer.start_file(format!("{}_{}.jpg", item_code, i + 1), zip_options)
-        {
-            return Ok(json!(Response {
-                result: "error".to_string(),
-                message: format!("error when zip start file error:{}", err)
-            }));
-        };
-
-        if let Err(err) = zip_writer.write_all(&image_byte) {
-            return Ok(json!(Response {
-                result: "error".to_string(),
-                message: format!("error when zip write file error:{}", err)
-            }));
-        };
-    }
-    if let Err(err) = zip_writer.start_file(format!("{}_size.jpg", item_code), zip_options) {
-        return Ok(json!(Response {
-            result: "error".to_string(),
-            message: format!("error when zip start file error:{}", err)
-        }));
-    }
-    if let Err(err) = zip_writer.write_all(&size_image_bytes) {
-        return Ok(json!(Response {
-            result: "error".to_string(),
-            message: format!("error when zip write file error:{}", err)
-        }));
-    };
-
-    if let Err(err) = zip_writer.finish() {
-        return Ok(json!(Response {
-            result: "error".to_string(),
-            message: format!("error when zip finish error:{}", err)
-        }));
+        match build_image_entries(&item_code, i + 1, &image_byte, &variant_widths, variant_format) {
+            Ok(image_entries) => entries.extend(image_entries),
+            Err(message) => {
+                return Ok(json!(Response {
+                    result: "error".to_string(),
+                    message
+                }));
+            }
+        }
     }
+    entries.push((format!("{}_size.jpg", item_code), size_image_bytes));
 
-    let zip_file_buf = zip_buf.into_inner();
-    println!("read buf length:{}", zip_file_buf.len());
-    let put_request = rusoto_s3::PutObjectRequest {
-        bucket: "phbundledimages".to_string(),
-        body: Some(zip_file_buf.into()),
-        key: format!("{}.zip", item_code),
-        ..Default::default()
-    };
-    if let Err(err) = s3_client.put_object(put_request).await {
+    if let Err(err) = s3_zip_upload::stream_zip_to_s3(
+        &s3_client,
+        &config.bundled_images_bucket,
+        &bundle_key,
+        entries,
+        compression_mode,
+        deflate_level,
+        &content_hash,
+    )
+    .await
+    {
         println!("error happened:{:?}", err);
         return Ok(json!(Response {
             result: "error".to_string(),
-            message: format!("put file error: {:?}", err)
+            message: format!("error when build and upload zip error: {:?}", err)
         }));
     }
     Ok(json!(Response {
@@ -347,9 +460,101 @@ async fn func(event: LambdaEvent<Value>) -> Result<Value, Error> {
     }))
 }
 
-async fn get_font_file(key: &str, s3_client: &S3Client) -> Result<Vec<u8>, Error> {
+/// Checks whether a single item image exists in S3 via a cheap
+/// `HeadObject` call, returning its ETag if so. `HeadObject` doesn't
+/// return a typed "not found" error body the way `GetObject` does, so
+/// any failure (missing object or otherwise) is treated as "no image",
+/// matching [`s3_zip_upload::existing_zip_matches`]'s handling of the
+/// same kind of call. The returned ETag feeds the content hash used to
+/// detect an already-built zip for this item, letting the caller skip
+/// the full-body download below entirely on a cache hit.
+async fn head_image(s3_client: &S3Client, bucket: &str, item_code: &str, no: u32) -> Option<String> {
+    let request = HeadObjectRequest {
+        bucket: bucket.to_string(),
+        key: format!("{}_{}.jpeg", item_code, no),
+        ..Default::default()
+    };
+    s3_client.head_object(request).await.ok()?.e_tag
+}
+
+/// Fetches a single item image's full body from S3, returning `None`
+/// when the object does not exist so the caller can skip it rather than
+/// aborting the whole request.
+async fn fetch_image(
+    s3_client: &S3Client,
+    bucket: &str,
+    item_code: &str,
+    no: u32,
+) -> Result<Option<(String, Vec<u8>)>, Error> {
+    let request = GetObjectRequest {
+        bucket: bucket.to_string(),
+        key: format!("{}_{}.jpeg", item_code, no),
+        ..Default::default()
+    };
+    let res = match s3_client.get_object(request).await {
+        Ok(object) => object,
+        Err(err) => {
+            if let RusotoError::Service(GetObjectError::NoSuchKey(_)) = err {
+                println!("no such key:{}", format_args!("{}_{}.jpeg", item_code, no));
+                return Ok(None);
+            }
+            return Err(Error::from(err.to_string()));
+        }
+    };
+    let etag = res.e_tag.clone().unwrap_or_default();
+    let res_body = res.body.unwrap();
+    let mut image_byte: Vec<u8> = Vec::new();
+    res_body
+        .into_async_read()
+        .read_to_end(&mut image_byte)
+        .await?;
+    println!(
+        "get image:{},len:{}",
+        format_args!("{}_{}.jpeg", item_code, no),
+        image_byte.len()
+    );
+    Ok(Some((etag, image_byte)))
+}
+
+/// Builds the zip entries for one image: the raw JPEG when
+/// `variant_widths` is empty (the default, unchanged behavior), or one
+/// `-{width}` variant per distinct width that [`make_variants`] produces
+/// otherwise. The archive's
+/// compression method per entry is decided later, by extension, in
+/// [`s3_zip_upload::stream_zip_to_s3`].
+fn build_image_entries(
+    item_code: &str,
+    no: usize,
+    image_byte: &[u8],
+    variant_widths: &[u32],
+    variant_format: VariantFormat,
+) -> Result<Vec<(String, Vec<u8>)>, String> {
+    if variant_widths.is_empty() {
+        return Ok(vec![(
+            format!("{}_{}.jpg", item_code, no),
+            image_byte.to_vec(),
+        )]);
+    }
+    let variants = make_variants(image_byte, variant_widths, variant_format)
+        .map_err(|err| format!("error when make image variants error:{:?}", err))?;
+    let extension = match variant_format {
+        VariantFormat::Webp => "webp",
+        VariantFormat::Jpeg => "jpg",
+    };
+    Ok(variants
+        .into_iter()
+        .map(|variant| {
+            (
+                format!("{}_{}-{}.{}", item_code, no, variant.width, extension),
+                variant.bytes,
+            )
+        })
+        .collect())
+}
+
+async fn get_font_file(bucket: &str, key: &str, s3_client: &S3Client) -> Result<Vec<u8>, Error> {
     let request = GetObjectRequest {
-        bucket: "phfunctionresource".into(),
+        bucket: bucket.into(),
         key: key.into(),
         ..Default::default()
     };
@@ -0,0 +1,358 @@
+use async_zip::{tokio::write::ZipFileWriter, Compression, ZipEntryBuilder};
+use bytes::{Bytes, BytesMut};
+use lambda_runtime::Error;
+use rusoto_s3::{
+    AbortMultipartUploadRequest, CompleteMultipartUploadRequest, CompletedMultipartUpload,
+    CompletedPart, CreateMultipartUploadRequest, HeadObjectRequest, S3Client, UploadPartRequest, S3,
+};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::AsyncWrite;
+use tokio::sync::mpsc::{self, Receiver};
+use tokio_util::sync::PollSender;
+
+/// S3 requires every part but the last to be at least 5 MiB; buffer a
+/// little above that so a large archive doesn't turn into one
+/// `UploadPart` call per image.
+const PART_SIZE_BYTES: usize = 6 * 1024 * 1024;
+
+/// Channel capacity between the zip writer and the uploader task. Kept
+/// small so `poll_write` genuinely blocks (backpressure) once this many
+/// chunks are waiting for the uploader, instead of letting the writer
+/// race arbitrarily far ahead of the S3 uploads.
+const CHANNEL_CAPACITY: usize = 2;
+
+/// A message sent from the zip-writing side to the uploader task.
+enum UploadMessage {
+    /// A chunk of zip bytes to buffer/upload.
+    Chunk(Bytes),
+    /// The zip writer failed partway through; abort the multipart
+    /// upload instead of completing it with a truncated archive.
+    Abort,
+}
+
+/// S3 object metadata key the built zip's content hash is stored under,
+/// so a later request for the same item can detect an "already built"
+/// archive without re-downloading or re-rendering anything.
+const CONTENT_HASH_METADATA_KEY: &str = "content-hash";
+
+/// Returns `true` when `bucket`/`key` already holds a zip built from the
+/// same inputs as `content_hash`, so the caller can skip rebuilding it.
+pub async fn existing_zip_matches(
+    s3_client: &S3Client,
+    bucket: &str,
+    key: &str,
+    content_hash: &str,
+) -> bool {
+    let request = HeadObjectRequest {
+        bucket: bucket.to_string(),
+        key: key.to_string(),
+        ..Default::default()
+    };
+    match s3_client.head_object(request).await {
+        Ok(head) => head
+            .metadata
+            .and_then(|metadata| metadata.get(CONTENT_HASH_METADATA_KEY).cloned())
+            .map(|existing_hash| existing_hash == content_hash)
+            .unwrap_or(false),
+        Err(_) => false,
+    }
+}
+
+/// Extensions that are already compressed (JPEG/WebP/PNG image data), for
+/// which `deflate` buys nothing and `stored` is always used instead.
+const PRECOMPRESSED_EXTENSIONS: &[&str] = &["jpg", "jpeg", "webp", "png"];
+
+/// Archive-wide compression mode, selected per request.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionMode {
+    Stored,
+    Deflate,
+}
+
+impl Default for CompressionMode {
+    fn default() -> Self {
+        CompressionMode::Stored
+    }
+}
+
+/// Deflate effort, mapped to the `flate2`/`zip` 0-9 level range.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DeflateLevel {
+    Fast,
+    Default,
+    Best,
+}
+
+impl DeflateLevel {
+    fn as_level(self) -> i32 {
+        match self {
+            DeflateLevel::Fast => 1,
+            DeflateLevel::Default => 6,
+            DeflateLevel::Best => 9,
+        }
+    }
+}
+
+impl Default for DeflateLevel {
+    fn default() -> Self {
+        DeflateLevel::Default
+    }
+}
+
+/// Picks the zip compression method for one entry: `stored` for any
+/// already-compressed image extension, otherwise whatever `mode` asks for.
+fn entry_compression(name: &str, mode: CompressionMode) -> Compression {
+    let extension = name.rsplit('.').next().unwrap_or("").to_ascii_lowercase();
+    if PRECOMPRESSED_EXTENSIONS.contains(&extension.as_str()) {
+        return Compression::Stored;
+    }
+    match mode {
+        CompressionMode::Stored => Compression::Stored,
+        CompressionMode::Deflate => Compression::Deflate,
+    }
+}
+
+/// Builds a zip archive from `entries` and uploads it to `bucket`/`key`
+/// as it is written, via an S3 multipart upload, instead of buffering
+/// the whole archive in memory first.
+pub async fn stream_zip_to_s3(
+    s3_client: &S3Client,
+    bucket: &str,
+    key: &str,
+    entries: Vec<(String, Vec<u8>)>,
+    compression_mode: CompressionMode,
+    deflate_level: DeflateLevel,
+    content_hash: &str,
+) -> Result<(), Error> {
+    let (sender, receiver) = mpsc::channel(CHANNEL_CAPACITY);
+    let uploader = tokio::spawn(run_upload(
+        receiver,
+        s3_client.clone(),
+        bucket.to_string(),
+        key.to_string(),
+        content_hash.to_string(),
+    ));
+
+    // Keep a plain clone to signal `Abort` below; the writer's own clone
+    // is wrapped for poll-based backpressure.
+    let abort_sender = sender.clone();
+    let mut zip_writer = ZipFileWriter::with_tokio(ChannelWriter {
+        sender: PollSender::new(sender),
+    });
+    let write_result: Result<(), Error> = async {
+        for (name, bytes) in entries {
+            let compression = entry_compression(&name, compression_mode);
+            let mut builder = ZipEntryBuilder::new(name.into(), compression);
+            if compression == Compression::Deflate {
+                builder = builder.compression_level(deflate_level.as_level());
+            }
+            zip_writer.write_entry_whole(builder, &bytes).await?;
+        }
+        zip_writer.close().await?;
+        Ok(())
+    }
+    .await;
+
+    if let Err(err) = write_result {
+        // The channel is only closed here if the uploader itself gave up
+        // first, in which case it has already aborted the upload (or never
+        // created one), so a failed send is ignored.
+        abort_sender.send(UploadMessage::Abort).await.ok();
+        uploader.await.ok();
+        return Err(err);
+    }
+
+    // The writer's sender is gone with `zip_writer`; dropping this last
+    // one closes the channel so `run_upload` completes the upload.
+    drop(abort_sender);
+    uploader.await.map_err(|err| Error::from(err.to_string()))?
+}
+
+/// Bridges the synchronous-looking `ZipFileWriter` to the multipart
+/// upload task: every write is forwarded over a bounded channel, so
+/// `poll_write` blocks while [`CHANNEL_CAPACITY`] chunks are already
+/// queued instead of racing arbitrarily far ahead of the uploader.
+struct ChannelWriter {
+    sender: PollSender<UploadMessage>,
+}
+
+impl AsyncWrite for ChannelWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.sender.poll_reserve(cx) {
+            Poll::Ready(Ok(())) => {
+                let chunk = UploadMessage::Chunk(Bytes::copy_from_slice(buf));
+                if self.sender.send_item(chunk).is_err() {
+                    return Poll::Ready(Err(std::io::Error::new(
+                        std::io::ErrorKind::BrokenPipe,
+                        "multipart upload task is gone",
+                    )));
+                }
+                Poll::Ready(Ok(buf.len()))
+            }
+            Poll::Ready(Err(_)) => Poll::Ready(Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "multipart upload task is gone",
+            ))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Drains the zip writer's byte stream, forwarding ~[`PART_SIZE_BYTES`]
+/// chunks to S3 as they fill. Completes the multipart upload once the
+/// channel closes cleanly, or aborts it if the zip writer signalled a
+/// failure (an explicit [`UploadMessage::Abort`]) or an upload itself
+/// failed partway through.
+async fn run_upload(
+    mut receiver: Receiver<UploadMessage>,
+    s3_client: S3Client,
+    bucket: String,
+    key: String,
+    content_hash: String,
+) -> Result<(), Error> {
+    let mut upload = MultipartZipUpload::create(s3_client, bucket, key, content_hash).await?;
+    while let Some(message) = receiver.recv().await {
+        match message {
+            UploadMessage::Chunk(chunk) => {
+                if let Err(err) = upload.write(&chunk).await {
+                    upload.abort().await.ok();
+                    return Err(err);
+                }
+            }
+            UploadMessage::Abort => {
+                upload.abort().await.ok();
+                return Err(Error::from(
+                    "zip writer failed; multipart upload aborted".to_string(),
+                ));
+            }
+        }
+    }
+    upload.finish().await
+}
+
+/// A single S3 multipart upload in progress, buffering written bytes
+/// until a full part is available to flush.
+struct MultipartZipUpload {
+    s3_client: S3Client,
+    bucket: String,
+    key: String,
+    upload_id: String,
+    buffer: BytesMut,
+    parts: Vec<CompletedPart>,
+    next_part_number: i64,
+}
+
+impl MultipartZipUpload {
+    async fn create(
+        s3_client: S3Client,
+        bucket: String,
+        key: String,
+        content_hash: String,
+    ) -> Result<Self, Error> {
+        let created = s3_client
+            .create_multipart_upload(CreateMultipartUploadRequest {
+                bucket: bucket.clone(),
+                key: key.clone(),
+                content_type: Some("application/zip".to_string()),
+                metadata: Some(HashMap::from([(
+                    CONTENT_HASH_METADATA_KEY.to_string(),
+                    content_hash,
+                )])),
+                ..Default::default()
+            })
+            .await?;
+        let upload_id = created
+            .upload_id
+            .ok_or("create_multipart_upload did not return an upload id")?;
+        Ok(Self {
+            s3_client,
+            bucket,
+            key,
+            upload_id,
+            buffer: BytesMut::new(),
+            parts: Vec::new(),
+            next_part_number: 1,
+        })
+    }
+
+    async fn write(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.buffer.extend_from_slice(bytes);
+        while self.buffer.len() >= PART_SIZE_BYTES {
+            let part = self.buffer.split_to(PART_SIZE_BYTES).freeze();
+            self.upload_part(part).await?;
+        }
+        Ok(())
+    }
+
+    async fn upload_part(&mut self, body: Bytes) -> Result<(), Error> {
+        let part_number = self.next_part_number;
+        self.next_part_number += 1;
+        let result = self
+            .s3_client
+            .upload_part(UploadPartRequest {
+                bucket: self.bucket.clone(),
+                key: self.key.clone(),
+                upload_id: self.upload_id.clone(),
+                part_number,
+                body: Some(body.to_vec().into()),
+                ..Default::default()
+            })
+            .await?;
+        let e_tag = result.e_tag.ok_or("upload_part did not return an ETag")?;
+        self.parts.push(CompletedPart {
+            e_tag: Some(e_tag),
+            part_number: Some(part_number),
+        });
+        Ok(())
+    }
+
+    async fn finish(mut self) -> Result<(), Error> {
+        if !self.buffer.is_empty() {
+            let part = self.buffer.split().freeze();
+            self.upload_part(part).await?;
+        }
+        self.s3_client
+            .complete_multipart_upload(CompleteMultipartUploadRequest {
+                bucket: self.bucket.clone(),
+                key: self.key.clone(),
+                upload_id: self.upload_id.clone(),
+                multipart_upload: Some(CompletedMultipartUpload {
+                    parts: Some(self.parts.clone()),
+                }),
+                ..Default::default()
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Releases any parts already stored in S3 when the zip-building
+    /// step fails partway through.
+    async fn abort(self) -> Result<(), Error> {
+        self.s3_client
+            .abort_multipart_upload(AbortMultipartUploadRequest {
+                bucket: self.bucket,
+                key: self.key,
+                upload_id: self.upload_id,
+                ..Default::default()
+            })
+            .await?;
+        Ok(())
+    }
+}